@@ -4,10 +4,12 @@
 
 use std::sync::Arc;
 use std::string::ToString;
+use std::collections::HashMap;
+use std::f32;
 
-use geometry::{Intersection, Boundable, BBox, BoundableGeom, DifferentialGeometry};
+use geometry::{Intersection, Boundable, BBox, BoundableGeom, SampleableGeom, BVH, DifferentialGeometry};
 use material::Material;
-use linalg;
+use linalg::{self, AnimatedTransform, Point, Vector};
 
 /// An instance of geometry in the scene that only receives light
 pub struct Receiver {
@@ -15,8 +17,8 @@ pub struct Receiver {
     geom: Arc<BoundableGeom + Send + Sync>,
     /// The material being used by this instance.
     pub material: Arc<Material + Send + Sync>,
-    /// The transform to world space
-    transform: linalg::Transform,
+    /// The animated transform to world space, evaluated at the ray's time
+    transform: AnimatedTransform,
     /// Tag to identify the instance
     pub tag: String,
 }
@@ -24,80 +26,193 @@ pub struct Receiver {
 impl Receiver {
     /// Create a new instance of some geometry in the scene
     pub fn new(geom: Arc<BoundableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
-               transform: linalg::Transform, tag: &str) -> Receiver {
+               transform: AnimatedTransform, tag: &str) -> Receiver {
         Receiver { geom: geom, material: material, transform: transform, tag: tag.to_string() }
     }
     /// Test the ray for intersection against this insance of geometry.
     /// returns Some(Intersection) if an intersection was found and None if not.
     /// If an intersection is found `ray.max_t` will be set accordingly
     pub fn intersect(&self, ray: &mut linalg::Ray) -> Option<(DifferentialGeometry, &Material)> {
-        let mut local = self.transform.inv_mul_ray(ray);
+        let transform = self.transform.transform(ray.time);
+        let mut local = transform.inv_mul_ray(ray);
         let mut dg = match self.geom.intersect(&mut local) {
             Some(dg) => dg,
             None => return None,
         };
         ray.max_t = local.max_t;
-        dg.p = self.transform * dg.p;
-        dg.n = self.transform * dg.n;
-        dg.ng = self.transform * dg.ng;
-        dg.dp_du = self.transform * dg.dp_du;
-        dg.dp_dv = self.transform * dg.dp_dv;
+        dg.p = transform * dg.p;
+        dg.n = transform * dg.n;
+        dg.ng = transform * dg.ng;
+        dg.dp_du = transform * dg.dp_du;
+        dg.dp_dv = transform * dg.dp_dv;
         Some((dg, &*self.material))
     }
 }
 
 impl Boundable for Receiver {
     fn bounds(&self) -> BBox {
-        self.transform * self.geom.bounds()
+        // Enclose the geometry's motion over the normalized shutter interval
+        self.transform.animation_bounds(&self.geom.bounds(), 0.0, 1.0)
     }
 }
 
-/// An instance of geometry in the scene that receives and emits light
-/// TODO: This is currently just a placeholder, emissive geometry isn't
-/// currently implemented. This is why it's identical to `Receiver` :P
+/// Power radiated by a point light of the given `emission` intensity,
+/// integrated uniformly over the sphere surrounding it.
+fn point_light_power(emission: f32) -> f32 {
+    emission * 4.0 * f32::consts::PI
+}
+
+/// Power radiated by an area light of the given `emission` radiance over a
+/// surface of `surface_area`, integrated over the hemisphere at each point.
+fn area_light_power(emission: f32, surface_area: f32) -> f32 {
+    emission * surface_area * f32::consts::PI
+}
+
+/// Convert an area-measure pdf (uniform over a surface of `area`) into the
+/// solid-angle pdf `sample_li` reports, given the squared distance from the
+/// shading point to the sampled point and the cosine of the angle between the
+/// surface normal and the direction back to the shading point. Returns `None`
+/// when the sampled point is seen edge-on (`cos_l` below the epsilon), where
+/// the conversion is singular.
+fn area_to_solid_angle_pdf(dist_sq: f32, cos_l: f32, area: f32) -> Option<f32> {
+    if cos_l < 1e-6 {
+        None
+    } else {
+        Some(dist_sq / (cos_l * area))
+    }
+}
+
+/// An instance of geometry in the scene that receives and emits light. An
+/// emitter with geometry is an area light, one without (`geom == None`) is a
+/// point/delta light positioned by its transform.
 pub struct Emitter {
-    /// The geometry that's being instanced.
-    /// TODO: We could make this an `Option` and then represent point lights
-    /// as an Emitter with no geometry!
-    geom: Arc<BoundableGeom + Send + Sync>,
+    /// The geometry that's being instanced, or `None` for a point light. Area
+    /// lights need to be sampled by point on their surface, so the geometry is
+    /// bounded on `SampleableGeom` rather than just `BoundableGeom`.
+    geom: Option<Arc<SampleableGeom + Send + Sync>>,
     /// The material being used by this instance.
     pub material: Arc<Material + Send + Sync>,
-    /// The transform to world space
-    transform: linalg::Transform,
+    /// The radiance emitted from the light's surface (or intensity for a
+    /// point light).
+    emission: f32,
+    /// The animated transform to world space, evaluated at the ray's time
+    transform: AnimatedTransform,
     /// Tag to identify the instance
     pub tag: String,
 }
 
-// TODO: It may look like we repeat a lot of code here but that won't be the case after I
-// actually implement the emitter and unify point lights within this design.
 impl Emitter {
-    /// Create a new instance of some geometry in the scene
-    pub fn new(geom: Arc<BoundableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
-               transform: linalg::Transform, tag: &str) -> Emitter {
-        Emitter { geom: geom, material: material, transform: transform, tag: tag.to_string() }
+    /// Create a new area light instancing some emissive geometry in the scene
+    pub fn area(geom: Arc<SampleableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
+                emission: f32, transform: AnimatedTransform, tag: &str) -> Emitter {
+        Emitter { geom: Some(geom), material: material, emission: emission,
+                  transform: transform, tag: tag.to_string() }
+    }
+    /// Create a new point light with the passed intensity at the transform's origin
+    pub fn point(material: Arc<Material + Send + Sync>, emission: f32,
+                 transform: AnimatedTransform, tag: &str) -> Emitter {
+        Emitter { geom: None, material: material, emission: emission,
+                  transform: transform, tag: tag.to_string() }
     }
     /// Test the ray for intersection against this insance of geometry.
     /// returns Some(Intersection) if an intersection was found and None if not.
-    /// If an intersection is found `ray.max_t` will be set accordingly
+    /// If an intersection is found `ray.max_t` will be set accordingly.
+    /// Point lights have no geometry and are never hit directly.
     pub fn intersect(&self, ray: &mut linalg::Ray) -> Option<(DifferentialGeometry, &Material)> {
-        let mut local = self.transform.inv_mul_ray(ray);
-        let mut dg = match self.geom.intersect(&mut local) {
+        let geom = match self.geom {
+            Some(ref g) => g,
+            None => return None,
+        };
+        let transform = self.transform.transform(ray.time);
+        let mut local = transform.inv_mul_ray(ray);
+        let mut dg = match geom.intersect(&mut local) {
             Some(dg) => dg,
             None => return None,
         };
         ray.max_t = local.max_t;
-        dg.p = self.transform * dg.p;
-        dg.n = self.transform * dg.n;
-        dg.ng = self.transform * dg.ng;
-        dg.dp_du = self.transform * dg.dp_du;
-        dg.dp_dv = self.transform * dg.dp_dv;
+        dg.p = transform * dg.p;
+        dg.n = transform * dg.n;
+        dg.ng = transform * dg.ng;
+        dg.dp_du = transform * dg.dp_du;
+        dg.dp_dv = transform * dg.dp_dv;
         Some((dg, &*self.material))
     }
+    /// Returns true if the light is a delta distribution (a point light), which
+    /// emits from a single point with infinite density and can't be sampled by
+    /// the BSDF.
+    pub fn is_delta(&self) -> bool {
+        self.geom.is_none()
+    }
+    /// Sample the incident radiance arriving at `p` from this light, returning
+    /// the direction towards the light `wi`, the emitted radiance along it, the
+    /// pdf of the sample (with respect to solid angle) and the distance to the
+    /// sampled point. The light's transform is evaluated at the shading `time`
+    /// so a moving area light is sampled consistently with the traced ray.
+    /// `samples` is the 2D sample used to pick a point on an area light's
+    /// surface and is ignored for point lights.
+    pub fn sample_li(&self, p: &Point, time: f32, samples: &(f32, f32)) -> (Vector, f32, f32, f32) {
+        let transform = self.transform.transform(time);
+        match self.geom {
+            // A point light is a delta distribution: the pdf is 1 and the
+            // arriving radiance falls off with the squared distance.
+            None => {
+                let pos = transform * Point::broadcast(0.0);
+                let w = pos - *p;
+                let dist = w.length();
+                let wi = w * (1.0 / dist);
+                (wi, self.emission / (dist * dist), 1.0, dist)
+            },
+            Some(ref geom) => {
+                let (ps, ns) = geom.sample(samples);
+                let pos = transform * ps;
+                let n = (transform * ns).normalized();
+                let w = pos - *p;
+                let dist = w.length();
+                let wi = w * (1.0 / dist);
+                // Convert the area pdf into a solid-angle pdf as seen from `p`
+                let cos_l = f32::abs(linalg::dot(&n, &-wi));
+                match area_to_solid_angle_pdf(dist * dist, cos_l, geom.surface_area()) {
+                    None => (wi, 0.0, 0.0, dist),
+                    Some(pdf) => (wi, self.emission, pdf, dist),
+                }
+            },
+        }
+    }
+    /// Compute the pdf of sampling the direction `wi` from `p` towards this
+    /// light, used to weight multiple-importance-sampling. Point lights can't be
+    /// reached by chance so their pdf is always 0. The transform is evaluated
+    /// at the shading `time` to match `sample_li`.
+    pub fn pdf_li(&self, p: &Point, time: f32, wi: &Vector) -> f32 {
+        match self.geom {
+            None => 0.0,
+            Some(ref geom) => {
+                let transform = self.transform.transform(time);
+                geom.pdf(&transform.inv_mul_point(p), &transform.inv_mul_vector(wi))
+            },
+        }
+    }
+    /// The total power emitted by this light, used to sample a light list
+    /// proportional to emitted power.
+    pub fn power(&self) -> f32 {
+        match self.geom {
+            Some(ref geom) => area_light_power(self.emission, geom.surface_area()),
+            None => point_light_power(self.emission),
+        }
+    }
 }
 
 impl Boundable for Emitter {
     fn bounds(&self) -> BBox {
-        self.transform * self.geom.bounds()
+        // Enclose the geometry's motion (or, for a point light, the swept
+        // position of its origin point) over the normalized shutter interval.
+        // Sampling through `animation_bounds` rather than just the endpoints
+        // matters here since Smooth/blend-graph transforms can bulge outside
+        // the segment between `t=0` and `t=1`.
+        let local_bounds = match self.geom {
+            Some(ref geom) => geom.bounds(),
+            None => BBox::new().point_union(&Point::broadcast(0.0)),
+        };
+        self.transform.animation_bounds(&local_bounds, 0.0, 1.0)
     }
 }
 
@@ -110,13 +225,18 @@ pub enum Instance {
 impl Instance {
     /// Create an instance of the geometry in the scene that will only receive light.
     pub fn receiver(geom: Arc<BoundableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
-               transform: linalg::Transform, tag: &str) -> Instance {
+               transform: AnimatedTransform, tag: &str) -> Instance {
         Instance::Receiver(Receiver::new(geom, material, transform, tag))
     }
-    /// Create an instance of the geometry in the scene that will emit and receive light
-    pub fn emitter(geom: Arc<BoundableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
-               transform: linalg::Transform, tag: &str) -> Instance {
-        Instance::Emitter(Emitter::new(geom, material, transform, tag))
+    /// Create an area light instancing emissive geometry in the scene
+    pub fn area(geom: Arc<SampleableGeom + Send + Sync>, material: Arc<Material + Send + Sync>,
+               emission: f32, transform: AnimatedTransform, tag: &str) -> Instance {
+        Instance::Emitter(Emitter::area(geom, material, emission, transform, tag))
+    }
+    /// Create a point light in the scene positioned by its transform
+    pub fn point(material: Arc<Material + Send + Sync>, emission: f32,
+               transform: AnimatedTransform, tag: &str) -> Instance {
+        Instance::Emitter(Emitter::point(material, emission, transform, tag))
     }
     /// Test the ray for intersection against this insance of geometry.
     /// returns Some(Intersection) if an intersection was found and None if not.
@@ -142,3 +262,151 @@ impl Boundable for Instance {
     }
 }
 
+/// A two-level acceleration structure over the scene's instances. The top
+/// level is a `BVH` over the world-space `bounds` of every `Instance`;
+/// traversal walks that tree and, for each candidate instance, transforms the
+/// ray into the instance's object space before descending into its geometry
+/// (see `Instance::intersect`). Whatever bottom-level tree that geometry
+/// builds over its own primitives is entirely up to the `BoundableGeom` (or
+/// `SampleableGeom`) implementation; `GeomCache` doesn't build or own one.
+///
+/// What `GeomCache` does provide is memoized construction: a scene that
+/// instances the same mesh thousands of times should only ever build that
+/// mesh once. Since two loader calls building "the same" mesh don't
+/// necessarily produce the same `Arc` (pointer identity can't catch that),
+/// `intern`/`intern_area` key the cache by the asset identity the loader
+/// already knows (e.g. the source file path) and only run the supplied
+/// builder closure the first time a key is seen; every later call for that
+/// key gets the cached `Arc` back without touching the builder at all.
+pub struct GeomCache {
+    /// Distinct `Receiver`/`Instance::receiver` geometry built so far, keyed
+    /// by the caller-supplied asset identity.
+    geometry: HashMap<String, Arc<BoundableGeom + Send + Sync>>,
+    /// Distinct area-light geometry built so far, kept separately since
+    /// `SampleableGeom` and `BoundableGeom` are different trait objects and
+    /// don't coerce into one another.
+    emitter_geometry: HashMap<String, Arc<SampleableGeom + Send + Sync>>,
+    /// Top-level BVH over the instances' world-space bounds, built by `build`.
+    bvh: Option<BVH<Instance>>,
+}
+
+impl GeomCache {
+    /// Create a new, empty geometry cache
+    pub fn new() -> GeomCache {
+        GeomCache { geometry: HashMap::new(), emitter_geometry: HashMap::new(), bvh: None }
+    }
+    /// Look up `key` (e.g. the mesh's source file path) and return its cached
+    /// geometry, building it with `build` and caching the result the first
+    /// time this key is seen. `build` is never called on a cache hit, so a
+    /// mesh instanced thousands of times is only ever constructed once no
+    /// matter how many `Instance`s end up referencing it.
+    pub fn intern<F>(&mut self, key: &str, build: F) -> Arc<BoundableGeom + Send + Sync>
+        where F: FnOnce() -> Arc<BoundableGeom + Send + Sync> {
+        if let Some(geom) = self.geometry.get(key) {
+            return geom.clone();
+        }
+        let geom = build();
+        self.geometry.insert(key.to_string(), geom.clone());
+        geom
+    }
+    /// The `SampleableGeom` analogue of `intern`, so instanced area lights get
+    /// the same memoized construction.
+    pub fn intern_area<F>(&mut self, key: &str, build: F) -> Arc<SampleableGeom + Send + Sync>
+        where F: FnOnce() -> Arc<SampleableGeom + Send + Sync> {
+        if let Some(geom) = self.emitter_geometry.get(key) {
+            return geom.clone();
+        }
+        let geom = build();
+        self.emitter_geometry.insert(key.to_string(), geom.clone());
+        geom
+    }
+    /// Build the top-level BVH over the passed instances.
+    pub fn build(&mut self, instances: Vec<Instance>) {
+        self.bvh = Some(BVH::new(4, instances));
+    }
+    /// Traverse the two-level structure and return the closest intersection.
+    /// The top-level BVH narrows the candidate instances and each candidate
+    /// descends into its own geometry.
+    pub fn intersect(&self, ray: &mut linalg::Ray) -> Option<Intersection> {
+        self.bvh.as_ref().and_then(|bvh| bvh.intersect(ray, |r, i| i.intersect(r)))
+    }
+    /// The number of distinct geometries cached, counting receiver and
+    /// emitter geometry together.
+    pub fn len(&self) -> usize {
+        self.geometry.len() + self.emitter_geometry.len()
+    }
+    /// Returns true if no geometry has been interned yet.
+    pub fn is_empty(&self) -> bool {
+        self.geometry.is_empty() && self.emitter_geometry.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct DummyGeom;
+    impl Boundable for DummyGeom {
+        fn bounds(&self) -> BBox {
+            BBox::new()
+        }
+    }
+    impl BoundableGeom for DummyGeom {
+        fn intersect(&self, _ray: &mut linalg::Ray) -> Option<DifferentialGeometry> {
+            None
+        }
+    }
+
+    #[test]
+    fn intern_only_builds_once_per_key() {
+        let mut cache = GeomCache::new();
+        let builds = Cell::new(0);
+        for _ in 0..3 {
+            cache.intern("bunny.obj", || {
+                builds.set(builds.get() + 1);
+                Arc::new(DummyGeom) as Arc<BoundableGeom + Send + Sync>
+            });
+        }
+        assert_eq!(builds.get(), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn intern_tracks_distinct_keys_separately() {
+        let mut cache = GeomCache::new();
+        cache.intern("a.obj", || Arc::new(DummyGeom) as Arc<BoundableGeom + Send + Sync>);
+        cache.intern("b.obj", || Arc::new(DummyGeom) as Arc<BoundableGeom + Send + Sync>);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.is_empty());
+    }
+
+    #[test]
+    fn point_light_power_is_isotropic_over_the_sphere() {
+        assert_eq!(point_light_power(1.0), 4.0 * f32::consts::PI);
+        assert_eq!(point_light_power(2.0), 2.0 * point_light_power(1.0));
+    }
+
+    #[test]
+    fn area_light_power_scales_with_emission_and_surface_area() {
+        assert_eq!(area_light_power(1.0, 1.0), f32::consts::PI);
+        assert_eq!(area_light_power(2.0, 3.0), 6.0 * f32::consts::PI);
+    }
+
+    #[test]
+    fn area_to_solid_angle_pdf_converts_by_distance_and_cosine() {
+        // A unit-area patch seen head-on (cos_l = 1.0) one unit away converts
+        // to a solid-angle pdf of 1.0
+        assert_eq!(area_to_solid_angle_pdf(1.0, 1.0, 1.0), Some(1.0));
+        // Doubling the distance quadruples the solid-angle pdf
+        assert_eq!(area_to_solid_angle_pdf(4.0, 1.0, 1.0), Some(4.0));
+        // Halving the cosine (more oblique) doubles it
+        assert_eq!(area_to_solid_angle_pdf(1.0, 0.5, 1.0), Some(2.0));
+    }
+
+    #[test]
+    fn area_to_solid_angle_pdf_is_none_when_seen_edge_on() {
+        assert_eq!(area_to_solid_angle_pdf(1.0, 0.0, 1.0), None);
+    }
+}
+
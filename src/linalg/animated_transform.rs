@@ -1,61 +1,324 @@
 //! Provides an animated transformation that moves an object between a
 //! set of specified keyframes.
 
-use std::collections::BTreeSet;
 use std::ops::Mul;
+use std::sync::Arc;
 
-use linalg::{self, keyframe, Keyframe, Transform};
+use linalg::{self, keyframe, Keyframe, Transform, Vector, Quaternion};
 use geometry::BBox;
 
+/// The way a keyframe is blended into the one that follows it in a track.
+/// `Step` holds the keyframe until the next one, `Linear` blends linearly and
+/// `Smooth` uses Catmull-Rom/squad splines for C1-continuous motion.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InterpolationMode {
+    /// Hold the keyframe's value until the next keyframe
+    Step,
+    /// Linearly blend translation and scale and slerp the rotation
+    Linear,
+    /// Catmull-Rom spline on translation and scale, squad on the rotation
+    Smooth,
+}
+
+/// Catmull-Rom spline through the four control points, evaluated at `t` in the
+/// segment between `p1` and `p2`.
+fn catmull_rom(t: f32, p0: &Vector, p1: &Vector, p2: &Vector, p3: &Vector) -> Vector {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (*p1 * 2.0 + (*p2 - *p0) * t + (*p0 * 2.0 - *p1 * 5.0 + *p2 * 4.0 - *p3) * t2
+        + (-*p0 + *p1 * 3.0 - *p2 * 3.0 + *p3) * t3) * 0.5
+}
+
+/// Hamilton product of two quaternions.
+fn qmul(a: &Quaternion, b: &Quaternion) -> Quaternion {
+    let w = a.w * b.w - a.v.dot(&b.v);
+    let v = b.v * a.w + a.v * b.w + linalg::cross(&a.v, &b.v);
+    Quaternion::new(w, v)
+}
+
+/// Conjugate of a unit quaternion, i.e. its inverse.
+fn qconj(a: &Quaternion) -> Quaternion {
+    Quaternion::new(a.w, -a.v)
+}
+
+/// Logarithm of a unit quaternion, returning a pure quaternion.
+fn qlog(a: &Quaternion) -> Quaternion {
+    let sin_theta = a.v.length();
+    if sin_theta < 1e-6 {
+        Quaternion::new(0.0, Vector::broadcast(0.0))
+    } else {
+        let theta = sin_theta.atan2(a.w);
+        Quaternion::new(0.0, a.v * (theta / sin_theta))
+    }
+}
+
+/// Exponential of a pure quaternion, returning a unit quaternion.
+fn qexp(a: &Quaternion) -> Quaternion {
+    let theta = a.v.length();
+    if theta < 1e-6 {
+        Quaternion::new(1.0, Vector::broadcast(0.0))
+    } else {
+        Quaternion::new(theta.cos(), a.v * (theta.sin() / theta))
+    }
+}
+
+/// Spherical linear interpolation between two unit quaternions.
+fn slerp(t: f32, a: &Quaternion, b: &Quaternion) -> Quaternion {
+    let mut dot = a.dot(b);
+    // Take the shorter arc by flipping one of the quaternions if needed
+    let b = if dot < 0.0 { dot = -dot; -*b } else { *b };
+    if dot > 0.9995 {
+        // The endpoints are nearly parallel, fall back to a normalized lerp
+        let v = a.v * (1.0 - t) + b.v * t;
+        let w = a.w * (1.0 - t) + b.w * t;
+        Quaternion::new(w, v).normalize()
+    } else {
+        let theta = dot.acos();
+        let sin_theta = theta.sin();
+        let w0 = ((1.0 - t) * theta).sin() / sin_theta;
+        let w1 = (t * theta).sin() / sin_theta;
+        Quaternion::new(a.w * w0 + b.w * w1, a.v * w0 + b.v * w1)
+    }
+}
+
+/// The intermediate control quaternion for squad, which smooths the path
+/// through `q1` using its neighbours `q0` and `q2`.
+fn squad_intermediate(q0: &Quaternion, q1: &Quaternion, q2: &Quaternion) -> Quaternion {
+    let inv = qconj(q1);
+    let c1 = qlog(&qmul(&inv, q0));
+    let c2 = qlog(&qmul(&inv, q2));
+    let exp = qexp(&Quaternion::new(0.0, -(c1.v + c2.v) * 0.25));
+    qmul(q1, &exp)
+}
+
+/// Spherical quadrangle interpolation between `q1` and `q2` using the
+/// surrounding keyframes `q0` and `q3` to keep the rotation C1-continuous.
+fn squad(t: f32, q0: &Quaternion, q1: &Quaternion, q2: &Quaternion, q3: &Quaternion) -> Quaternion {
+    let a = squad_intermediate(q0, q1, q2);
+    let b = squad_intermediate(q1, q2, q3);
+    slerp(2.0 * t * (1.0 - t), &slerp(t, q1, q2), &slerp(t, &a, &b))
+}
+
+/// A keyframe together with the mode used to interpolate out of it towards the
+/// next keyframe in the track. The mode is stored per keyframe so a single
+/// track can mix held, linear and smooth segments.
+#[derive(Debug, Clone)]
+pub struct TrackKey {
+    /// The transform keyframe
+    pub frame: Keyframe,
+    /// How this keyframe blends into the one that follows it
+    pub interpolation: InterpolationMode,
+}
+
+/// Pair each keyframe with the default `Linear` interpolation mode.
+fn default_modes(keyframes: Vec<Keyframe>) -> Vec<TrackKey> {
+    keyframes.into_iter()
+             .map(|frame| TrackKey { frame: frame, interpolation: InterpolationMode::Linear })
+             .collect()
+}
+
+/// Interpolate a time-sorted keyframe track at `time`, returning the blended
+/// transform. The bracketing keyframe pair is found by binary search in
+/// O(log n), clamping to the endpoints when `time` is outside the range, and
+/// the left keyframe's `interpolation` mode governs the segment.
+fn sample_track(track: &[TrackKey], time: f32) -> Transform {
+    if track.len() == 1 {
+        return track[0].frame.transform();
+    }
+    let hi = match track.binary_search_by(|k| k.frame.time.partial_cmp(&time).unwrap()) {
+        // Landed exactly on a keyframe, no blending needed
+        Ok(i) => return track[i].frame.transform(),
+        Err(i) => i,
+    };
+    if hi == 0 {
+        return track[0].frame.transform();
+    }
+    if hi == track.len() {
+        return track[track.len() - 1].frame.transform();
+    }
+    let lo = hi - 1;
+    let k1 = &track[lo];
+    let k2 = &track[hi];
+    match k1.interpolation {
+        InterpolationMode::Step => k1.frame.transform(),
+        InterpolationMode::Linear => keyframe::interpolate(time, &k1.frame, &k2.frame),
+        InterpolationMode::Smooth => {
+            let t = (time - k1.frame.time) / (k2.frame.time - k1.frame.time);
+            let k0 = &track[lo.saturating_sub(1)].frame;
+            let k3 = &track[(hi + 1).min(track.len() - 1)].frame;
+            let translation = catmull_rom(t, &k0.translation, &k1.frame.translation,
+                                          &k2.frame.translation, &k3.translation);
+            let scaling = catmull_rom(t, &k0.scaling, &k1.frame.scaling, &k2.frame.scaling, &k3.scaling);
+            let rotation = squad(t, &k0.rotation, &k1.frame.rotation, &k2.frame.rotation, &k3.rotation);
+            compose(&translation, &rotation, &scaling)
+        },
+    }
+}
+
+/// Collect keyframes into a track sorted ascending by time.
+fn sorted_track(mut track: Vec<TrackKey>) -> Vec<TrackKey> {
+    track.sort_by(|a, b| a.frame.time.partial_cmp(&b.frame.time).unwrap());
+    track
+}
+
+/// Decompose a transform into its translation, rotation quaternion and scale
+/// so that two transforms can be blended component-wise.
+fn decompose(t: &Transform) -> (Vector, Quaternion, Vector) {
+    let k = Keyframe::new(t, 0.0);
+    (k.translation, k.rotation, k.scaling)
+}
+
+/// Recompose a translation, rotation and scale back into a single transform.
+fn compose(translation: &Vector, rotation: &Quaternion, scaling: &Vector) -> Transform {
+    Transform::translate(translation) * Transform::from(*rotation) * Transform::scale(scaling)
+}
+
+/// A node in an animation blend graph. A `Clip` wraps a keyframe track and is
+/// evaluated by interpolating it directly, while a `Blend` holds a weight per
+/// child and combines their transforms. Children are held behind an `Arc` so a
+/// single clip or blend node can be shared by several parents, making the graph
+/// a true DAG rather than a tree; evaluation walks it bottom-up at a given time.
+#[derive(Debug, Clone)]
+pub enum BlendNode {
+    /// A time-sorted keyframe track, interpolated at the evaluation time.
+    Clip(Vec<TrackKey>),
+    /// A weighted blend of child nodes, `(weight, child)` per entry. Sharing a
+    /// child `Arc` across entries lets the same motion feed multiple blends.
+    Blend(Vec<(f32, Arc<BlendNode>)>),
+}
+
+impl BlendNode {
+    /// Create a clip node wrapping the passed keyframe track, interpolating
+    /// every segment in the default `Linear` mode. Panics if `keyframes` is
+    /// empty, since `eval` has no keyframe to fall back on.
+    pub fn clip(keyframes: Vec<Keyframe>) -> BlendNode {
+        assert!(!keyframes.is_empty(), "A clip needs at least one keyframe");
+        BlendNode::Clip(sorted_track(default_modes(keyframes)))
+    }
+    /// Create a clip node from keyframes paired with their interpolation
+    /// modes. Panics if `keyframes` is empty, since `eval` has no keyframe to
+    /// fall back on.
+    pub fn clip_modes(keyframes: Vec<(Keyframe, InterpolationMode)>) -> BlendNode {
+        assert!(!keyframes.is_empty(), "A clip needs at least one keyframe");
+        let track = keyframes.into_iter()
+                             .map(|(frame, interpolation)| TrackKey { frame: frame, interpolation: interpolation })
+                             .collect();
+        BlendNode::Clip(sorted_track(track))
+    }
+    /// Create an empty blend node; children are attached with `add_child`
+    pub fn blend() -> BlendNode {
+        BlendNode::Blend(Vec::new())
+    }
+    /// Attach a weighted child to a blend node. The child is shared, so the same
+    /// node may be added to more than one parent. Panics if called on a clip node.
+    pub fn add_child(&mut self, weight: f32, child: Arc<BlendNode>) {
+        match *self {
+            BlendNode::Blend(ref mut children) => children.push((weight, child)),
+            BlendNode::Clip(_) => panic!("Can't add a child to a clip node"),
+        }
+    }
+    /// Set the blend weight of the child at `index`. Panics if called on a clip
+    /// node or if `index` is out of range.
+    pub fn set_weight(&mut self, index: usize, weight: f32) {
+        match *self {
+            BlendNode::Blend(ref mut children) => children[index].0 = weight,
+            BlendNode::Clip(_) => panic!("Can't set a child weight on a clip node"),
+        }
+    }
+    /// Evaluate the node at `time`, producing the transform for this subtree.
+    fn eval(&self, time: f32) -> Transform {
+        match *self {
+            BlendNode::Clip(ref track) => sample_track(track, time),
+            BlendNode::Blend(ref children) => {
+                let total: f32 = children.iter().fold(0.0, |s, &(w, _)| s + w);
+                if total == 0.0 {
+                    return Transform::identity();
+                }
+                let mut translation = Vector::broadcast(0.0);
+                let mut scaling = Vector::broadcast(0.0);
+                // Accumulate the rotations in a single hemisphere so the
+                // normalized weighted blend doesn't cancel itself out.
+                let mut rot_v = Vector::broadcast(0.0);
+                let mut rot_w = 0.0;
+                let mut reference: Option<Quaternion> = None;
+                for &(weight, ref child) in children {
+                    let w = weight / total;
+                    let (t, mut r, s) = decompose(&child.eval(time));
+                    translation = translation + t * w;
+                    scaling = scaling + s * w;
+                    match reference {
+                        None => reference = Some(r),
+                        Some(ref q) => if q.dot(&r) < 0.0 {
+                            r = -r;
+                        },
+                    }
+                    rot_v = rot_v + r.v * w;
+                    rot_w += r.w * w;
+                }
+                let rotation = Quaternion::new(rot_w, rot_v).normalize();
+                compose(&translation, &rotation, &scaling)
+            },
+        }
+    }
+}
+
 /// An animated transform that blends between the keyframes in its transformation
-/// list over time.
+/// list over time, or evaluates a blend graph if one was supplied.
 #[derive(Debug, Clone)]
 pub struct AnimatedTransform {
     /// List of animated transforms in hierarchical order, e.g. the lowest
     /// index is the object's, index 1 holds its direct parent's transform, etc.
-    keyframes: Vec<BTreeSet<Keyframe>>,
+    /// Each track is kept sorted ascending by keyframe time.
+    keyframes: Vec<Vec<TrackKey>>,
+    /// Optional blend graph, used in place of the keyframe stack when present
+    /// so that independent motions can be layered with adjustable influence.
+    graph: Option<BlendNode>,
 }
 
 impl AnimatedTransform {
     /// Create a new empty animated transform
     pub fn new() -> AnimatedTransform {
-        AnimatedTransform { keyframes: Vec::new() }
+        AnimatedTransform { keyframes: Vec::new(), graph: None }
     }
-    /// Create an animated transformation blending between the passed keyframes
+    /// Create an animated transformation blending between the passed keyframes,
+    /// interpolating every segment in the default `Linear` mode
     pub fn with_keyframes(keyframes: Vec<Keyframe>) -> AnimatedTransform {
-        AnimatedTransform { keyframes: vec![keyframes.into_iter().collect()] }
+        AnimatedTransform { keyframes: vec![sorted_track(default_modes(keyframes))], graph: None }
+    }
+    /// Create an animated transformation from keyframes paired with the
+    /// interpolation mode used to blend out of each one
+    pub fn with_keyframe_modes(keyframes: Vec<(Keyframe, InterpolationMode)>) -> AnimatedTransform {
+        let track = keyframes.into_iter()
+                             .map(|(frame, interpolation)| TrackKey { frame: frame, interpolation: interpolation })
+                             .collect();
+        AnimatedTransform { keyframes: vec![sorted_track(track)], graph: None }
+    }
+    /// Create an animated transformation driven by a blend graph. The root
+    /// node's output replaces the transform computed at each time point.
+    pub fn from_graph(root: BlendNode) -> AnimatedTransform {
+        AnimatedTransform { keyframes: Vec::new(), graph: Some(root) }
     }
-    /// Insert a keyframe into the animation sequence
+    /// Insert a keyframe into the animation sequence, keeping the track sorted.
+    /// The segment out of the keyframe uses the default `Linear` mode.
     pub fn insert(&mut self, keyframe: Keyframe) {
-        self.keyframes[0].insert(keyframe);
+        let key = TrackKey { frame: keyframe, interpolation: InterpolationMode::Linear };
+        let track = &mut self.keyframes[0];
+        let pos = track.binary_search_by(|k| k.frame.time.partial_cmp(&key.frame.time).unwrap())
+                       .unwrap_or_else(|i| i);
+        track.insert(pos, key);
     }
     /// Compute the transformation matrix for the animation at some time point.
-    /// The transform is found by interpolating the two keyframes nearest to the
-    /// time point being evaluated. **TODO** a binary search of some kind to find
-    /// the two keyframes to blend would be much better.
+    /// When a blend graph is present its root is evaluated, otherwise the
+    /// bracketing keyframes are found by binary search and interpolated.
     pub fn transform(&self, time: f32) -> Transform {
+        if let Some(ref root) = self.graph {
+            return root.eval(time);
+        }
         let mut transform = Transform::identity();
         // Step through the transform stack, applying each animation transform at this
         // time as we move up
         for stack in &self.keyframes[..] {
-            let t =
-                if stack.len() == 1 {
-                    let first = stack.iter().next().unwrap();
-                    first.transform()
-                } else {
-                    // TODO: Binary search here somehow? Or does the BTreeSet have some faster impl
-                    // of take/skip while?
-                    let first = stack.iter().take_while(|k| k.time < time).last();
-                    let second = stack.iter().skip_while(|k| k.time < time).next();
-                    if first.is_none() {
-                        stack.iter().next().unwrap().transform()
-                    } else if second.is_none() {
-                        stack.iter().last().unwrap().transform()
-                    } else {
-                        keyframe::interpolate(time, first.unwrap(), second.unwrap())
-                    }
-                };
-            transform = t * transform;
+            transform = sample_track(stack, time) * transform;
         }
         transform
     }
@@ -76,17 +339,112 @@ impl AnimatedTransform {
     }
     /// Check if the transform is actually animated
     pub fn is_animated(&self) -> bool {
+        if self.graph.is_some() {
+            return true;
+        }
         self.keyframes.is_empty() || self.keyframes.iter().fold(true, |b, stack| b && stack.len() > 1)
     }
 }
 
 impl Mul for AnimatedTransform {
     type Output = AnimatedTransform;
-    /// Compose the animated transformations
+    /// Compose the animated transformations, stacking `self`'s keyframe tracks
+    /// on top of `rhs`'s so they're evaluated together in `transform`. Blend
+    /// graphs can't be folded into this stack, so composing either side with
+    /// one present is a programmer error rather than something to silently drop.
     fn mul(self, mut rhs: AnimatedTransform) -> AnimatedTransform {
+        assert!(self.graph.is_none() && rhs.graph.is_none(),
+                "Can't compose a blend-graph-driven AnimatedTransform with another");
         for l in &self.keyframes[..] {
             rhs.keyframes.push(l.clone());
         }
         rhs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linalg::{Transform, Vector, Quaternion, Keyframe};
+
+    fn key(time: f32, x: f32) -> Keyframe {
+        Keyframe::new(&Transform::translate(&Vector::new(x, 0.0, 0.0)), time)
+    }
+
+    fn translation_x(t: &Transform) -> f32 {
+        Keyframe::new(t, 0.0).translation.x
+    }
+
+    #[test]
+    fn bracket_single_keyframe() {
+        let track = default_modes(vec![key(0.0, 1.0)]);
+        // A single-keyframe track is constant no matter where it's sampled
+        assert_eq!(translation_x(&sample_track(&track, -5.0)), 1.0);
+        assert_eq!(translation_x(&sample_track(&track, 5.0)), 1.0);
+    }
+
+    #[test]
+    fn bracket_clamps_before_first_and_after_last() {
+        let track = default_modes(vec![key(0.0, 0.0), key(1.0, 10.0)]);
+        assert_eq!(translation_x(&sample_track(&track, -1.0)), 0.0);
+        assert_eq!(translation_x(&sample_track(&track, 2.0)), 10.0);
+    }
+
+    #[test]
+    fn bracket_exact_hit_needs_no_blending() {
+        let track = default_modes(vec![key(0.0, 0.0), key(1.0, 10.0), key(2.0, 20.0)]);
+        assert_eq!(translation_x(&sample_track(&track, 1.0)), 10.0);
+    }
+
+    #[test]
+    fn bracket_interpolates_between_keyframes() {
+        let track = default_modes(vec![key(0.0, 0.0), key(2.0, 10.0)]);
+        assert_eq!(translation_x(&sample_track(&track, 1.0)), 5.0);
+    }
+
+    #[test]
+    fn slerp_returns_endpoints_at_t_zero_and_one() {
+        let a = Quaternion::new(1.0, Vector::broadcast(0.0));
+        let b = Quaternion::new(0.0, Vector::new(0.0, 0.0, 1.0)).normalize();
+        let start = slerp(0.0, &a, &b);
+        let end = slerp(1.0, &a, &b);
+        assert!((start.w - a.w).abs() < 1e-5 && (start.v - a.v).length() < 1e-5);
+        assert!((end.w - b.w).abs() < 1e-5 && (end.v - b.v).length() < 1e-5);
+    }
+
+    #[test]
+    fn slerp_stays_normalized_halfway() {
+        let a = Quaternion::new(1.0, Vector::broadcast(0.0));
+        let b = Quaternion::new(0.0, Vector::new(0.0, 1.0, 0.0)).normalize();
+        let mid = slerp(0.5, &a, &b);
+        let len = (mid.w * mid.w + mid.v.dot(&mid.v)).sqrt();
+        assert!((len - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn squad_reproduces_inner_keyframes_at_endpoints() {
+        let q0 = Quaternion::new(1.0, Vector::broadcast(0.0));
+        let q1 = Quaternion::new(0.0, Vector::new(1.0, 0.0, 0.0)).normalize();
+        let q2 = Quaternion::new(0.0, Vector::new(0.0, 1.0, 0.0)).normalize();
+        let q3 = Quaternion::new(0.0, Vector::new(0.0, 0.0, 1.0)).normalize();
+        let start = squad(0.0, &q0, &q1, &q2, &q3);
+        let end = squad(1.0, &q0, &q1, &q2, &q3);
+        assert!((start.w - q1.w).abs() < 1e-4 && (start.v - q1.v).length() < 1e-4);
+        assert!((end.w - q2.w).abs() < 1e-4 && (end.v - q2.v).length() < 1e-4);
+    }
+
+    #[test]
+    fn blend_graph_weighted_average_of_two_clips() {
+        let mut root = BlendNode::blend();
+        root.add_child(1.0, Arc::new(BlendNode::clip(vec![key(0.0, 0.0)])));
+        root.add_child(1.0, Arc::new(BlendNode::clip(vec![key(0.0, 10.0)])));
+        assert_eq!(translation_x(&root.eval(0.0)), 5.0);
+    }
+
+    #[test]
+    fn blend_graph_zero_total_weight_is_identity() {
+        let mut root = BlendNode::blend();
+        root.add_child(0.0, Arc::new(BlendNode::clip(vec![key(0.0, 42.0)])));
+        assert_eq!(translation_x(&root.eval(0.0)), 0.0);
+    }
+}